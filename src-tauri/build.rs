@@ -1,54 +1,86 @@
+use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-fn main() {
-    // Copy sidecar binaries to target directory for development
-    let target_os = std::env::var("CARGO_CFG_TARGET_OS").unwrap_or_else(|_| "unknown".to_string());
-    let target_arch = std::env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_else(|_| "unknown".to_string());
+/// Returns the exact target triple cargo is building for. Read straight
+/// from `TARGET` (which cargo always sets for build scripts) rather than
+/// re-derived from `CARGO_CFG_*` pieces, since reconstructing it tends to
+/// get the less common triples (armv7's `gnueabihf`, Android's
+/// `aarch64-linux-android` shape, ...) wrong in ways that silently break
+/// sidecar binary resolution at runtime.
+fn target_triple() -> String {
+    env::var("TARGET").expect("cargo always sets TARGET for build scripts")
+}
 
-    // Determine the target triple for the sidecar binary
-    let target_triple = match (target_os.as_str(), target_arch.as_str()) {
-        ("macos", "aarch64") => "aarch64-apple-darwin",
-        ("macos", "x86_64") => "x86_64-apple-darwin",
-        ("windows", "x86_64") => "x86_64-pc-windows-msvc",
-        ("linux", "x86_64") => "x86_64-unknown-linux-gnu",
-        _ => {
-            println!("cargo:warning=Unknown target platform: {} {}", target_os, target_arch);
-            return tauri_build::build();
-        }
-    };
+/// Reads the `bundle.externalBin` list from `tauri.conf.json` and returns
+/// the base file name of each entry (without the `-{triple}` suffix Tauri
+/// appends when resolving the actual binary for the active target).
+fn external_bin_names(manifest_dir: &Path) -> Vec<String> {
+    let config_path = manifest_dir.join("tauri.conf.json");
+    let contents = fs::read_to_string(&config_path)
+        .unwrap_or_else(|e| panic!("Failed to read {:?}: {}", config_path, e));
+    let config: serde_json::Value = serde_json::from_str(&contents)
+        .unwrap_or_else(|e| panic!("Failed to parse {:?}: {}", config_path, e));
 
-    let binary_name = if target_os == "windows" {
-        format!("vn-sidecar-{}.exe", target_triple)
-    } else {
-        format!("vn-sidecar-{}", target_triple)
-    };
+    config
+        .pointer("/bundle/externalBin")
+        .and_then(|value| value.as_array())
+        .map(|entries| {
+            entries
+                .iter()
+                .filter_map(|entry| entry.as_str())
+                .filter_map(|path| Path::new(path).file_name())
+                .map(|name| name.to_string_lossy().into_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
 
-    // Source: src-tauri/binaries/vn-sidecar-{triple}
-    let source = PathBuf::from("binaries").join(&binary_name);
+fn main() {
+    let manifest_dir = PathBuf::from(
+        env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR is always set by cargo"),
+    );
+    let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap_or_else(|_| "unknown".to_string());
+    let triple = target_triple();
+    let sidecar_names = external_bin_names(&manifest_dir);
 
-    // Destination: target/{profile}/binaries/vn-sidecar-{triple}
-    let target_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap())
+    if sidecar_names.is_empty() {
+        println!("cargo:warning=No externalBin entries found in tauri.conf.json; skipping sidecar copy");
+        return tauri_build::build();
+    }
+
+    // Destination: target/{profile}/binaries/{name}-{triple}
+    let target_dir = PathBuf::from(env::var("OUT_DIR").unwrap())
         .ancestors()
         .nth(3)
         .unwrap()
         .join("binaries");
 
-    // Create target binaries directory if it doesn't exist
-    if let Err(e) = fs::create_dir_all(&target_dir) {
-        println!("cargo:warning=Failed to create binaries directory: {}", e);
-        return tauri_build::build();
-    }
+    fs::create_dir_all(&target_dir)
+        .unwrap_or_else(|e| panic!("Failed to create binaries directory {:?}: {}", target_dir, e));
 
-    let dest = target_dir.join(&binary_name);
+    for base_name in &sidecar_names {
+        let binary_name = if target_os == "windows" {
+            format!("{}-{}.exe", base_name, triple)
+        } else {
+            format!("{}-{}", base_name, triple)
+        };
 
-    // Copy sidecar binary if source exists
-    if source.exists() {
-        match fs::copy(&source, &dest) {
-            Ok(_) => println!("cargo:warning=Copied sidecar binary to {:?}", dest),
-            Err(e) => println!("cargo:warning=Failed to copy sidecar binary: {}", e),
+        println!("cargo:rerun-if-changed=binaries/{}", binary_name);
+
+        let source = manifest_dir.join("binaries").join(&binary_name);
+        if !source.exists() {
+            panic!(
+                "Required sidecar binary not found at {:?}. Run 'python src-python/build_sidecar.py' first.",
+                source
+            );
         }
 
+        let dest = target_dir.join(&binary_name);
+        fs::copy(&source, &dest).unwrap_or_else(|e| {
+            panic!("Failed to copy sidecar binary {:?} to {:?}: {}", source, dest, e)
+        });
+
         // On Unix, ensure executable permissions
         #[cfg(unix)]
         {
@@ -59,12 +91,7 @@ fn main() {
                 let _ = fs::set_permissions(&dest, perms);
             }
         }
-    } else {
-        println!("cargo:warning=Sidecar binary not found at {:?}. Run 'python src-python/build_sidecar.py' first.", source);
     }
 
-    // Rebuild if source binary changes
-    println!("cargo:rerun-if-changed=binaries/{}", binary_name);
-
     tauri_build::build()
 }
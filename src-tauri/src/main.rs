@@ -2,102 +2,425 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 use std::sync::{Arc, Mutex};
-use tauri::{Emitter, Manager, State};
+use std::time::{Duration, Instant};
+use log::{error, info, warn};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::Serialize;
+use tauri::{AppHandle, Emitter, Manager, State};
+use tauri_plugin_shell::process::CommandChild;
 use tauri_plugin_shell::ShellExt;
+use tokio_util::sync::CancellationToken;
 
-// State to store the Python sidecar port
+/// Initial delay before the first respawn attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+/// Upper bound the backoff is allowed to double into.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// If the sidecar stays up at least this long, the backoff and retry
+/// counter are reset as if it were a fresh start.
+const HEALTHY_UPTIME: Duration = Duration::from_secs(10);
+/// Give up and emit `sidecar-failed` after this many consecutive
+/// failed respawns.
+const MAX_RETRIES: u32 = 8;
+/// Env var the sidecar reads its expected handshake token from.
+const HANDSHAKE_TOKEN_ENV: &str = "VN_HANDSHAKE_TOKEN";
+/// How long to wait for the sidecar to exit after a graceful stop
+/// signal before we forcibly kill it.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+/// Log target the sidecar's own output (and supervisor lifecycle events)
+/// are recorded under, so they're easy to filter in the log viewer.
+const SIDECAR_LOG_TARGET: &str = "sidecar";
+/// Cap on the rotating app log file (which holds the sidecar's records
+/// alongside the app's own) before a new one is started.
+const LOG_MAX_FILE_SIZE_BYTES: u128 = 10 * 1024 * 1024;
+
+/// The port and auth token the frontend needs to talk to the sidecar.
+#[derive(Clone, Serialize)]
+struct SidecarSession {
+    port: u16,
+    token: String,
+}
+
+/// A running supervisor loop's cancellation handle, so a newer call to
+/// `spawn_sidecar` can stop it and wait for it to actually exit before
+/// starting a replacement, instead of two loops racing over the same
+/// state.
+type SupervisorHandle = (CancellationToken, tauri::async_runtime::JoinHandle<()>);
+
+// State to store the Python sidecar's current session
 #[derive(Default)]
 struct SidecarState {
-    port: Arc<Mutex<Option<u16>>>,
+    session: Arc<Mutex<Option<SidecarSession>>>,
+    // The currently-running sidecar process, if any, so it can be torn
+    // down cleanly on app exit.
+    child: Arc<Mutex<Option<CommandChild>>>,
+    // The supervisor loop currently responsible for `child`, if any. At
+    // most one supervisor is ever active: starting a new one always
+    // cancels and awaits the previous one first.
+    supervisor: Arc<Mutex<Option<SupervisorHandle>>>,
+}
+
+/// Sends the sidecar a graceful stop signal over stdin, then schedules a
+/// forced kill after a grace period in case it doesn't exit on its own.
+fn begin_sidecar_shutdown(child_state: &Arc<Mutex<Option<CommandChild>>>) {
+    let child = child_state.lock().unwrap().take();
+    if let Some(mut child) = child {
+        let _ = child.write(b"shutdown\n");
+
+        tauri::async_runtime::spawn(async move {
+            tokio::time::sleep(SHUTDOWN_GRACE_PERIOD).await;
+            let _ = child.kill();
+        });
+    }
+}
+
+/// Cancels the currently-running supervisor loop (if any) and waits for
+/// it to exit, then gracefully stops whatever sidecar child it left
+/// behind. Awaiting the old loop's exit before returning is what
+/// guarantees only one supervisor is ever live at a time. The old child
+/// is not awaited: it may still be running through its shutdown grace
+/// period when this returns.
+async fn stop_supervisor(
+    supervisor_state: Arc<Mutex<Option<SupervisorHandle>>>,
+    child_state: Arc<Mutex<Option<CommandChild>>>,
+) {
+    let existing = supervisor_state.lock().unwrap().take();
+    if let Some((cancel, task)) = existing {
+        cancel.cancel();
+        let _ = task.await;
+    }
+
+    begin_sidecar_shutdown(&child_state);
+}
+
+/// Kills the current sidecar child immediately, with no grace period.
+/// Used when the process can't be trusted to honor a graceful stop (a
+/// handshake it failed, or one already reported as errored) and would
+/// otherwise be silently abandoned, still running, once we drop it.
+fn kill_child_now(child_state: &Arc<Mutex<Option<CommandChild>>>) {
+    if let Some(mut child) = child_state.lock().unwrap().take() {
+        let _ = child.kill();
+    }
+}
+
+/// Generates a random handshake token from the OS CSPRNG, since this is
+/// a shared secret guarding the loopback server and needs real entropy.
+fn generate_handshake_token() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Logs a sidecar stderr line at a level inferred from its own prefix,
+/// since Python's logging module already tags lines this way.
+fn log_sidecar_stderr(line: &str) {
+    let trimmed = line.trim_start();
+    if trimmed.starts_with("WARN") {
+        warn!(target: SIDECAR_LOG_TARGET, "{}", line);
+    } else {
+        // Treat anything else on stderr (including unprefixed tracebacks)
+        // as an error; it's the conservative default for this stream.
+        error!(target: SIDECAR_LOG_TARGET, "{}", line);
+    }
 }
 
 // Command to get the sidecar port
 #[tauri::command]
 fn get_sidecar_port(state: State<SidecarState>) -> Result<u16, String> {
-    let port = state.port.lock().unwrap();
-    match *port {
-        Some(p) => Ok(p),
+    let session = state.session.lock().unwrap();
+    match session.as_ref() {
+        Some(session) => Ok(session.port),
         None => Err("Sidecar port not yet available".to_string()),
     }
 }
 
-fn main() {
-    tauri::Builder::default()
-        .plugin(tauri_plugin_shell::init())
-        .plugin(tauri_plugin_dialog::init())
-        .manage(SidecarState::default())
-        .setup(|app| {
-            let handle = app.handle().clone();
-            let state: State<SidecarState> = handle.state();
-            let port_state = state.port.clone();
+// Command to get the full sidecar session (port + handshake token) so the
+// frontend can authenticate its requests to the loopback server.
+#[tauri::command]
+fn get_sidecar_session(state: State<SidecarState>) -> Result<SidecarSession, String> {
+    let session = state.session.lock().unwrap();
+    session
+        .clone()
+        .ok_or_else(|| "Sidecar session not yet available".to_string())
+}
+
+/// Spawns the Python sidecar and supervises it, respawning with
+/// exponential backoff whenever it exits. Gives up (emitting
+/// `sidecar-failed`) after more than `MAX_RETRIES` consecutive quick
+/// failures; a failure to create or spawn the command ends supervision
+/// with no retry. Checks
+/// `cancel` at every await point (the event loop and the backoff sleep)
+/// so a caller that cancels it is guaranteed no further respawn happens.
+async fn supervise_sidecar(
+    handle: AppHandle,
+    session_state: Arc<Mutex<Option<SidecarSession>>>,
+    child_state: Arc<Mutex<Option<CommandChild>>>,
+    cancel: CancellationToken,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut retries = 0u32;
 
-            // Spawn the Python sidecar
-            tauri::async_runtime::spawn(async move {
-                println!("Starting Python sidecar...");
+    while !cancel.is_cancelled() {
+        info!(target: SIDECAR_LOG_TARGET, "Starting Python sidecar...");
 
-                let shell = handle.shell();
+        let token = generate_handshake_token();
+        let shell = handle.shell();
+        let sidecar_command = shell
+            .sidecar("vn-sidecar")
+            .map(|command| command.env(HANDSHAKE_TOKEN_ENV, &token));
 
-                // Create sidecar command
-                let sidecar_command = shell.sidecar("vn-sidecar");
+        let command = match sidecar_command {
+            Ok(command) => command,
+            Err(e) => {
+                error!(target: SIDECAR_LOG_TARGET, "Failed to create sidecar command: {}", e);
+                error!(target: SIDECAR_LOG_TARGET, "Note: For development, you can run the Python server manually:");
+                error!(target: SIDECAR_LOG_TARGET, "  cd src-python && ./run.sh");
+                return;
+            }
+        };
 
-                match sidecar_command {
-                    Ok(command) => {
-                        // Spawn the sidecar process
-                        let (mut rx, _child) = command
-                            .spawn()
-                            .expect("Failed to spawn Python sidecar");
+        let (mut rx, child) = match command.spawn() {
+            Ok(pair) => pair,
+            Err(e) => {
+                error!(target: SIDECAR_LOG_TARGET, "Failed to spawn Python sidecar: {}", e);
+                return;
+            }
+        };
+        *child_state.lock().unwrap() = Some(child);
 
-                        // Listen to stdout to capture the port
-                        while let Some(event) = rx.recv().await {
-                            match event {
-                                tauri_plugin_shell::process::CommandEvent::Stdout(line) => {
-                                    let line_str = String::from_utf8_lossy(&line);
-                                    println!("Sidecar stdout: {}", line_str);
+        let started_at = Instant::now();
+        let mut terminated = false;
+        let mut was_cancelled = false;
+        let mut handshake_done = false;
 
-                                    // Extract port from "SERVER_PORT=12345" format
-                                    if line_str.contains("SERVER_PORT=") {
-                                        if let Some(port_str) = line_str.split('=').nth(1) {
-                                            if let Ok(port) = port_str.trim().parse::<u16>() {
-                                                println!("Extracted sidecar port: {}", port);
+        loop {
+            tokio::select! {
+                biased;
+                _ = cancel.cancelled() => {
+                    was_cancelled = true;
+                    break;
+                }
+                maybe_event = rx.recv() => {
+                    let Some(event) = maybe_event else { break; };
+                    match event {
+                        tauri_plugin_shell::process::CommandEvent::Stdout(line) => {
+                            let line_str = String::from_utf8_lossy(&line);
 
-                                                // Store the port in state
-                                                let mut port_lock = port_state.lock().unwrap();
-                                                *port_lock = Some(port);
+                            if !handshake_done {
+                                match serde_json::from_str::<serde_json::Value>(line_str.trim()) {
+                                    Ok(handshake) => {
+                                        handshake_done = true;
+                                        let port = handshake.get("port").and_then(|v| v.as_u64());
+                                        let received_token = handshake.get("token").and_then(|v| v.as_str());
 
-                                                // Emit event to frontend
-                                                let _ = handle.emit("sidecar-port", port);
+                                        match (port, received_token) {
+                                            (Some(port), Some(received_token)) if received_token == token => {
+                                                info!(target: SIDECAR_LOG_TARGET, "Sidecar handshake verified, port {}", port);
 
-                                                println!("Sidecar port stored and emitted to frontend");
+                                                let session = SidecarSession {
+                                                    port: port as u16,
+                                                    token: token.clone(),
+                                                };
+                                                *session_state.lock().unwrap() = Some(session.clone());
+                                                let _ = handle.emit("sidecar-port", session.port);
+                                            }
+                                            _ => {
+                                                error!(target: SIDECAR_LOG_TARGET, "Sidecar handshake rejected: token mismatch or malformed payload");
+                                                kill_child_now(&child_state);
+                                                terminated = true;
+                                                break;
                                             }
                                         }
                                     }
+                                    // Not the handshake line (and thus safe to surface as-is);
+                                    // log it like any other stdout line.
+                                    Err(_) => {
+                                        info!(target: SIDECAR_LOG_TARGET, "{}", line_str);
+                                    }
                                 }
-                                tauri_plugin_shell::process::CommandEvent::Stderr(line) => {
-                                    let line_str = String::from_utf8_lossy(&line);
-                                    eprintln!("Sidecar stderr: {}", line_str);
-                                }
-                                tauri_plugin_shell::process::CommandEvent::Error(err) => {
-                                    eprintln!("Sidecar error: {}", err);
-                                }
-                                tauri_plugin_shell::process::CommandEvent::Terminated(payload) => {
-                                    eprintln!("Sidecar terminated with code: {:?}", payload.code);
-                                    break;
-                                }
-                                _ => {}
+                            } else {
+                                info!(target: SIDECAR_LOG_TARGET, "{}", line_str);
                             }
                         }
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to create sidecar command: {}", e);
-                        eprintln!("Note: For development, you can run the Python server manually:");
-                        eprintln!("  cd src-python && ./run.sh");
+                        tauri_plugin_shell::process::CommandEvent::Stderr(line) => {
+                            let line_str = String::from_utf8_lossy(&line);
+                            log_sidecar_stderr(&line_str);
+                        }
+                        tauri_plugin_shell::process::CommandEvent::Error(err) => {
+                            error!(target: SIDECAR_LOG_TARGET, "Sidecar error: {}", err);
+                            kill_child_now(&child_state);
+                            terminated = true;
+                            break;
+                        }
+                        tauri_plugin_shell::process::CommandEvent::Terminated(payload) => {
+                            warn!(target: SIDECAR_LOG_TARGET, "Sidecar terminated with code: {:?}", payload.code);
+                            terminated = true;
+                            break;
+                        }
+                        _ => {}
                     }
                 }
-            });
+            }
+        }
+
+        if was_cancelled {
+            // A newer supervisor is taking over (or we're shutting down);
+            // leave any cleanup of the child to whoever cancelled us.
+            return;
+        }
+
+        if !terminated {
+            // The event channel closed without an explicit Error/Terminated
+            // event (e.g. the app is shutting down); stop supervising.
+            return;
+        }
+
+        *session_state.lock().unwrap() = None;
+        *child_state.lock().unwrap() = None;
+
+        let _ = handle.emit("sidecar-down", ());
+
+        if started_at.elapsed() >= HEALTHY_UPTIME {
+            backoff = INITIAL_BACKOFF;
+            retries = 0;
+        } else {
+            retries += 1;
+        }
+
+        if retries > MAX_RETRIES {
+            error!(target: SIDECAR_LOG_TARGET, "Sidecar failed {} times in a row, giving up", retries);
+            let _ = handle.emit("sidecar-failed", ());
+            return;
+        }
+
+        info!(target: SIDECAR_LOG_TARGET, "Respawning sidecar in {:?} (attempt {})", backoff, retries);
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            _ = tokio::time::sleep(backoff) => {}
+        }
+        backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+    }
+}
+
+/// Tears down any currently-running sidecar (awaiting its supervisor
+/// loop's exit first, so at most one is ever active) and brings up a
+/// fresh supervised instance. Shared by the initial `setup` spawn and the
+/// start/stop/restart commands below.
+async fn spawn_sidecar(
+    handle: AppHandle,
+    session_state: Arc<Mutex<Option<SidecarSession>>>,
+    child_state: Arc<Mutex<Option<CommandChild>>>,
+    supervisor_state: Arc<Mutex<Option<SupervisorHandle>>>,
+) {
+    stop_supervisor(supervisor_state.clone(), child_state.clone()).await;
+
+    let cancel = CancellationToken::new();
+    let task = tauri::async_runtime::spawn(supervise_sidecar(
+        handle,
+        session_state,
+        child_state,
+        cancel.clone(),
+    ));
+
+    *supervisor_state.lock().unwrap() = Some((cancel, task));
+}
+
+// Command to bring up the sidecar if it isn't already running. A no-op
+// if one is already active; use `restart_sidecar` to force a fresh one.
+#[tauri::command]
+async fn start_sidecar(handle: AppHandle, state: State<'_, SidecarState>) -> Result<(), ()> {
+    if state.child.lock().unwrap().is_some() {
+        return Ok(());
+    }
+    spawn_sidecar(
+        handle,
+        state.session.clone(),
+        state.child.clone(),
+        state.supervisor.clone(),
+    )
+    .await;
+    Ok(())
+}
+
+// Command to stop the sidecar without restarting it.
+#[tauri::command]
+async fn stop_sidecar(state: State<'_, SidecarState>) -> Result<(), ()> {
+    stop_supervisor(state.supervisor.clone(), state.child.clone()).await;
+    Ok(())
+}
+
+// Command to tear down the current sidecar and bring up a fresh one, e.g.
+// to recover from a hung backend or apply a config change that requires
+// a reload.
+#[tauri::command]
+async fn restart_sidecar(handle: AppHandle, state: State<'_, SidecarState>) -> Result<(), ()> {
+    spawn_sidecar(
+        handle,
+        state.session.clone(),
+        state.child.clone(),
+        state.supervisor.clone(),
+    )
+    .await;
+    Ok(())
+}
+
+fn main() {
+    let app = tauri::Builder::default()
+        .plugin(
+            tauri_plugin_log::Builder::new()
+                .targets([
+                    tauri_plugin_log::Target::new(tauri_plugin_log::TargetKind::LogDir {
+                        file_name: Some("videonote".to_string()),
+                    }),
+                    tauri_plugin_log::Target::new(tauri_plugin_log::TargetKind::Webview),
+                ])
+                .max_file_size(LOG_MAX_FILE_SIZE_BYTES)
+                .rotation_strategy(tauri_plugin_log::RotationStrategy::KeepOne)
+                .build(),
+        )
+        .plugin(tauri_plugin_shell::init())
+        .plugin(tauri_plugin_dialog::init())
+        .manage(SidecarState::default())
+        .setup(|app| {
+            let handle = app.handle().clone();
+            let state: State<SidecarState> = handle.state();
+            let session_state = state.session.clone();
+            let child_state = state.child.clone();
+            let supervisor_state = state.supervisor.clone();
+
+            tauri::async_runtime::spawn(spawn_sidecar(handle, session_state, child_state, supervisor_state));
 
             Ok(())
         })
-        .invoke_handler(tauri::generate_handler![get_sidecar_port])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .on_window_event(|window, event| {
+            // `CloseRequested` can be vetoed (e.g. by a future "unsaved
+            // changes" prompt calling `api.prevent_close()`), so tearing the
+            // sidecar down here could kill it out from under a window that
+            // ends up staying open. `Destroyed` fires once the window is
+            // actually, irrevocably gone.
+            if let tauri::WindowEvent::Destroyed = event {
+                let state: State<SidecarState> = window.state();
+                let supervisor_state = state.supervisor.clone();
+                let child_state = state.child.clone();
+                tauri::async_runtime::spawn(stop_supervisor(supervisor_state, child_state));
+            }
+        })
+        .invoke_handler(tauri::generate_handler![
+            get_sidecar_port,
+            get_sidecar_session,
+            start_sidecar,
+            stop_sidecar,
+            restart_sidecar
+        ])
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application");
+
+    app.run(|app_handle, event| {
+        if let tauri::RunEvent::ExitRequested { .. } = event {
+            let state: State<SidecarState> = app_handle.state();
+            let supervisor_state = state.supervisor.clone();
+            let child_state = state.child.clone();
+            tauri::async_runtime::spawn(stop_supervisor(supervisor_state, child_state));
+        }
+    });
 }